@@ -0,0 +1,414 @@
+/*!
+Factory for launching new index tokens.
+
+The owner uploads the index token WASM once with `store_code`. Anyone can then
+call `create_index` with enough attached NEAR to deploy and initialize a new
+index on a subaccount of the factory (`<name>.<factory>`). The factory keeps a
+record of every index it has launched and retains a creation fee per launch.
+*/
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::Vector;
+use near_sdk::json_types::{Base64VecU8, U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{
+    env, is_promise_success, near_bindgen, require, AccountId, Balance, BorshStorageKey, Gas,
+    PanicOnDefault, Promise,
+};
+
+/// Storage key of the raw index token WASM uploaded by the owner.
+const CODE_KEY: &[u8] = b"code";
+
+const INIT_GAS: Gas = Gas(50_000_000_000_000);
+const CALLBACK_GAS: Gas = Gas(10_000_000_000_000);
+
+/// Balance a new index account keeps on top of what its code storage costs.
+const MIN_INDEX_BALANCE: Balance = 3_000_000_000_000_000_000_000_000;
+
+#[derive(BorshStorageKey, BorshSerialize)]
+enum StorageKey {
+    Indexes,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct IndexInfo {
+    pub account_id: AccountId,
+    pub creator_id: AccountId,
+    pub created_at: U64,
+}
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
+pub struct IndexFactory {
+    owner_id: AccountId,
+    creation_fee: Balance,
+    collected_fees: Balance,
+    indexes: Vector<IndexInfo>,
+}
+
+#[near_bindgen]
+impl IndexFactory {
+    #[init]
+    pub fn new(owner_id: AccountId, creation_fee: U128) -> Self {
+        require!(!env::state_exists(), "Already initialized");
+        Self {
+            owner_id,
+            creation_fee: creation_fee.into(),
+            collected_fees: 0,
+            indexes: Vector::new(StorageKey::Indexes),
+        }
+    }
+
+    /// Stores the index token WASM passed as the raw (non-JSON) input of the call.
+    pub fn store_code(&mut self) {
+        self.assert_owner();
+        let code = env::input().expect("Expected the contract code as input");
+        env::storage_write(CODE_KEY, &code);
+    }
+
+    /// Deploys the stored code to `<name>.<factory>` and calls its `new` method with `args`.
+    /// The attached deposit minus the creation fee funds the new account. The full deposit
+    /// is refunded if any step fails.
+    #[payable]
+    pub fn create_index(&mut self, name: String, args: Base64VecU8) -> Promise {
+        let code = env::storage_read(CODE_KEY).expect("Index code has not been stored");
+        // The factory can only create its direct subaccounts.
+        require!(!name.contains('.'), "Index name must not contain '.'");
+        let account_id = format!("{}.{}", name, env::current_account_id());
+        require!(
+            env::is_valid_account_id(account_id.as_bytes()),
+            "Invalid index name"
+        );
+        let account_id = AccountId::new_unchecked(account_id);
+
+        let attached = env::attached_deposit();
+        let required = (code.len() as Balance)
+            .checked_mul(env::storage_byte_cost())
+            .and_then(|storage_cost| storage_cost.checked_add(self.creation_fee))
+            .and_then(|amount| amount.checked_add(MIN_INDEX_BALANCE))
+            .expect("Required deposit overflows u128");
+        require!(
+            attached >= required,
+            format!("Attached deposit must be at least {}", required)
+        );
+
+        Promise::new(account_id.clone())
+            .create_account()
+            .transfer(attached - self.creation_fee)
+            .deploy_contract(code)
+            .function_call("new".to_string(), args.into(), 0, INIT_GAS)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(CALLBACK_GAS)
+                    .on_index_created(
+                        account_id,
+                        env::predecessor_account_id(),
+                        U128(attached),
+                        U128(self.creation_fee),
+                    ),
+            )
+    }
+
+    #[private]
+    pub fn on_index_created(
+        &mut self,
+        account_id: AccountId,
+        creator_id: AccountId,
+        attached: U128,
+        fee: U128,
+    ) -> bool {
+        if is_promise_success() {
+            self.indexes.push(&IndexInfo {
+                account_id,
+                creator_id,
+                created_at: env::block_timestamp().into(),
+            });
+            self.collected_fees = self
+                .collected_fees
+                .checked_add(fee.0)
+                .expect("Collected fees overflow u128");
+            true
+        } else {
+            env::log_str(&format!("Failed to create index {}, refunding", account_id));
+            Promise::new(creator_id).transfer(attached.0);
+            false
+        }
+    }
+
+    pub fn update_creation_fee(&mut self, creation_fee: U128) {
+        self.assert_owner();
+        self.creation_fee = creation_fee.into();
+    }
+
+    /// Sends all creation fees collected so far to the owner.
+    pub fn withdraw_fees(&mut self) -> Promise {
+        self.assert_owner();
+        require!(self.collected_fees > 0, "No fees to withdraw");
+        let amount = std::mem::take(&mut self.collected_fees);
+        Promise::new(self.owner_id.clone()).transfer(amount)
+    }
+
+    pub fn get_indexes(&self, from_index: u64, limit: u64) -> Vec<IndexInfo> {
+        (from_index..std::cmp::min(from_index.saturating_add(limit), self.indexes.len()))
+            .filter_map(|index| self.indexes.get(index))
+            .collect()
+    }
+
+    pub fn get_index_count(&self) -> u64 {
+        self.indexes.len()
+    }
+
+    pub fn get_creation_fee(&self) -> U128 {
+        self.creation_fee.into()
+    }
+
+    pub fn get_collected_fees(&self) -> U128 {
+        self.collected_fees.into()
+    }
+
+    fn assert_owner(&self) {
+        require!(
+            env::predecessor_account_id() == self.owner_id,
+            "Only the owner can call this method"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::mock::VmAction;
+    use near_sdk::test_utils::{accounts, get_created_receipts, VMContextBuilder};
+    use near_sdk::{serde_json, testing_env, PromiseResult, RuntimeFeesConfig, VMConfig};
+
+    const FEE: Balance = 1_000_000_000_000_000_000_000_000;
+    const CODE: &[u8] = &[0u8; 100];
+
+    fn factory_id() -> AccountId {
+        "factory.near".parse().unwrap()
+    }
+
+    fn context(predecessor: AccountId) -> VMContextBuilder {
+        let mut builder = VMContextBuilder::new();
+        builder
+            .current_account_id(factory_id())
+            .predecessor_account_id(predecessor);
+        builder
+    }
+
+    fn setup() -> IndexFactory {
+        testing_env!(context(accounts(0)).build());
+        let mut contract = IndexFactory::new(accounts(0), U128(FEE));
+        let mut builder = context(accounts(0));
+        builder.context.input = CODE.to_vec();
+        testing_env!(builder.build());
+        contract.store_code();
+        contract
+    }
+
+    fn required_deposit() -> Balance {
+        FEE + CODE.len() as Balance * env::storage_byte_cost() + MIN_INDEX_BALANCE
+    }
+
+    fn resolve_created(contract: &mut IndexFactory, result: PromiseResult) -> bool {
+        testing_env!(
+            context(factory_id()).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![result],
+        );
+        contract.on_index_created(
+            "top10.factory.near".parse().unwrap(),
+            accounts(1),
+            U128(required_deposit()),
+            U128(FEE),
+        )
+    }
+
+    #[test]
+    fn test_create_index_with_required_deposit() {
+        let mut contract = setup();
+        testing_env!(context(accounts(1))
+            .attached_deposit(required_deposit())
+            .build());
+        contract.create_index("top10".to_string(), Base64VecU8(b"{}".to_vec()));
+
+        let receipts = get_created_receipts();
+        assert_eq!(receipts.len(), 2);
+        assert_eq!(receipts[0].receiver_id.as_str(), "top10.factory.near");
+        assert_eq!(
+            receipts[0].actions,
+            vec![
+                VmAction::CreateAccount,
+                VmAction::Transfer {
+                    deposit: required_deposit() - FEE
+                },
+                VmAction::DeployContract {
+                    code: CODE.to_vec()
+                },
+                VmAction::FunctionCall {
+                    function_name: "new".to_string(),
+                    args: b"{}".to_vec(),
+                    gas: INIT_GAS,
+                    deposit: 0,
+                },
+            ]
+        );
+
+        assert_eq!(receipts[1].receiver_id, factory_id());
+        match &receipts[1].actions[..] {
+            [VmAction::FunctionCall {
+                function_name,
+                args,
+                gas,
+                deposit: 0,
+            }] => {
+                assert_eq!(function_name, "on_index_created");
+                assert_eq!(*gas, CALLBACK_GAS);
+                let args: serde_json::Value = serde_json::from_slice(args).unwrap();
+                assert_eq!(
+                    args,
+                    serde_json::json!({
+                        "account_id": "top10.factory.near",
+                        "creator_id": accounts(1),
+                        "attached": required_deposit().to_string(),
+                        "fee": FEE.to_string(),
+                    })
+                );
+            }
+            actions => panic!("Unexpected callback actions {:?}", actions),
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Required deposit overflows u128")]
+    fn test_create_index_required_deposit_overflow() {
+        let mut contract = setup();
+        contract.update_creation_fee(U128(u128::MAX));
+        testing_env!(context(accounts(1))
+            .attached_deposit(required_deposit())
+            .build());
+        contract.create_index("top10".to_string(), Base64VecU8(vec![]));
+    }
+
+    #[test]
+    #[should_panic(expected = "Attached deposit must be at least 4001000000000000000000000")]
+    fn test_create_index_insufficient_deposit() {
+        let mut contract = setup();
+        // 1 NEAR fee + 100 bytes of code at 10^19 per byte + 3 NEAR minimum balance.
+        assert_eq!(required_deposit(), 4_001_000_000_000_000_000_000_000);
+        testing_env!(context(accounts(1))
+            .attached_deposit(required_deposit() - 1)
+            .build());
+        contract.create_index("top10".to_string(), Base64VecU8(vec![]));
+    }
+
+    #[test]
+    #[should_panic(expected = "Index name must not contain '.'")]
+    fn test_create_index_nested_name() {
+        let mut contract = setup();
+        testing_env!(context(accounts(1))
+            .attached_deposit(required_deposit())
+            .build());
+        contract.create_index("a.b".to_string(), Base64VecU8(vec![]));
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid index name")]
+    fn test_create_index_invalid_name() {
+        let mut contract = setup();
+        testing_env!(context(accounts(1))
+            .attached_deposit(required_deposit())
+            .build());
+        contract.create_index("Top10".to_string(), Base64VecU8(vec![]));
+    }
+
+    #[test]
+    fn test_on_index_created_success() {
+        let mut contract = setup();
+        assert!(resolve_created(
+            &mut contract,
+            PromiseResult::Successful(vec![])
+        ));
+        assert_eq!(contract.get_index_count(), 1);
+        assert_eq!(contract.get_collected_fees().0, FEE);
+        let index = &contract.get_indexes(0, 10)[0];
+        assert_eq!(index.account_id.as_str(), "top10.factory.near");
+        assert_eq!(index.creator_id, accounts(1));
+    }
+
+    #[test]
+    fn test_on_index_created_failure() {
+        let mut contract = setup();
+        assert!(!resolve_created(&mut contract, PromiseResult::Failed));
+        assert_eq!(contract.get_index_count(), 0);
+        assert_eq!(contract.get_collected_fees().0, 0);
+
+        let receipts = get_created_receipts();
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(receipts[0].receiver_id, accounts(1));
+        assert_eq!(
+            receipts[0].actions,
+            vec![VmAction::Transfer {
+                deposit: required_deposit()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_get_indexes_pagination() {
+        let mut contract = setup();
+        for _ in 0..3 {
+            resolve_created(&mut contract, PromiseResult::Successful(vec![]));
+        }
+        assert_eq!(contract.get_indexes(0, 2).len(), 2);
+        assert_eq!(contract.get_indexes(1, 10).len(), 2);
+        assert_eq!(contract.get_indexes(3, 10).len(), 0);
+        assert_eq!(contract.get_indexes(10, 10).len(), 0);
+        assert_eq!(contract.get_indexes(1, u64::MAX).len(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method")]
+    fn test_store_code_not_owner() {
+        let mut contract = setup();
+        let mut builder = context(accounts(1));
+        builder.context.input = CODE.to_vec();
+        testing_env!(builder.build());
+        contract.store_code();
+    }
+
+    #[test]
+    fn test_update_creation_fee() {
+        let mut contract = setup();
+        contract.update_creation_fee(U128(FEE * 2));
+        assert_eq!(contract.get_creation_fee().0, FEE * 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method")]
+    fn test_update_creation_fee_not_owner() {
+        let mut contract = setup();
+        testing_env!(context(accounts(1)).build());
+        contract.update_creation_fee(U128(0));
+    }
+
+    #[test]
+    fn test_withdraw_fees() {
+        let mut contract = setup();
+        resolve_created(&mut contract, PromiseResult::Successful(vec![]));
+        testing_env!(context(accounts(0)).build());
+        contract.withdraw_fees();
+        assert_eq!(contract.get_collected_fees().0, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method")]
+    fn test_withdraw_fees_not_owner() {
+        let mut contract = setup();
+        resolve_created(&mut contract, PromiseResult::Successful(vec![]));
+        testing_env!(context(accounts(1)).build());
+        contract.withdraw_fees();
+    }
+}
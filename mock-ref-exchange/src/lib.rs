@@ -0,0 +1,676 @@
+/*!
+Mock of the Ref Finance exchange for deterministic testing.
+
+Implements the subset of the Ref interface the index contract talks to:
+token deposits through `ft_on_transfer`, `swap`, `withdraw`, `get_return` and
+`get_deposits`. Pools trade at a fixed configured rate, every swap loses
+`slippage_bps` against the quote, and swaps through a given pool or withdraws of
+a given token can be told to panic or burn all their gas. That lets a single
+constituent's leg fail while the others succeed, so the index contract's
+callbacks can be exercised against success, partial-failure and
+gas-exhaustion scenarios.
+
+Accounts must be registered with `storage_deposit` before they can deposit,
+swap or withdraw, otherwise the call fails with E10 like on Ref. Registration
+itself is free: any attached amount is recorded and reported as available.
+
+Output tokens are paid out of whatever FT balance the mock holds, so tests
+should fund it with a plain `ft_transfer` of each constituent first.
+*/
+use std::collections::HashMap;
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::{LookupMap, Vector};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{
+    assert_one_yocto, env, ext_contract, is_promise_success, near_bindgen, require, AccountId,
+    Balance, BorshStorageKey, Gas, PanicOnDefault, Promise, PromiseOrValue,
+};
+
+const GAS_FOR_FT_TRANSFER: Gas = Gas(10_000_000_000_000);
+const GAS_FOR_RESOLVE_WITHDRAW: Gas = Gas(10_000_000_000_000);
+const BPS_DENOMINATOR: u128 = 10_000;
+
+#[ext_contract(ext_ft)]
+pub trait FungibleToken {
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>);
+}
+
+#[derive(BorshStorageKey, BorshSerialize)]
+enum StorageKey {
+    Pools,
+    Deposits,
+    StorageBalances,
+    SwapFailures,
+    WithdrawFailures,
+}
+
+/// Same shape as Ref's `SwapAction`.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapAction {
+    pub pool_id: u64,
+    pub token_in: AccountId,
+    pub amount_in: Option<U128>,
+    pub token_out: AccountId,
+    pub min_amount_out: U128,
+}
+
+/// Pool trading at a fixed rate: `amount_b = amount_a * rate_numerator / rate_denominator`.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct MockPool {
+    pub token_a: AccountId,
+    pub token_b: AccountId,
+    pub rate_numerator: U128,
+    pub rate_denominator: U128,
+}
+
+/// Same shape as the NEP-145 `StorageBalance` Ref returns from `storage_deposit`.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StorageBalance {
+    pub total: U128,
+    pub available: U128,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum FailureMode {
+    None,
+    /// Panic at the start of the call.
+    Panic,
+    /// Spin until the prepaid gas is used up.
+    ExhaustGas,
+}
+
+impl FailureMode {
+    fn apply(self, method: &str) {
+        match self {
+            FailureMode::None => {}
+            FailureMode::Panic => panic!("Injected failure in {}", method),
+            FailureMode::ExhaustGas => loop {
+                env::used_gas();
+            },
+        }
+    }
+}
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
+pub struct MockRefExchange {
+    pools: Vector<MockPool>,
+    deposits: LookupMap<AccountId, HashMap<AccountId, Balance>>,
+    storage_balances: LookupMap<AccountId, Balance>,
+    slippage_bps: u32,
+    /// Injected failures for swaps through a pool, keyed by pool id.
+    swap_failures: LookupMap<u64, FailureMode>,
+    /// Injected failures for withdraws of a token, keyed by token id.
+    withdraw_failures: LookupMap<AccountId, FailureMode>,
+}
+
+#[near_bindgen]
+impl MockRefExchange {
+    #[init]
+    pub fn new() -> Self {
+        require!(!env::state_exists(), "Already initialized");
+        Self {
+            pools: Vector::new(StorageKey::Pools),
+            deposits: LookupMap::new(StorageKey::Deposits),
+            storage_balances: LookupMap::new(StorageKey::StorageBalances),
+            slippage_bps: 0,
+            swap_failures: LookupMap::new(StorageKey::SwapFailures),
+            withdraw_failures: LookupMap::new(StorageKey::WithdrawFailures),
+        }
+    }
+
+    /// Adds a fixed-rate pool and returns its id, like Ref's `add_simple_pool`.
+    pub fn add_pool(
+        &mut self,
+        token_a: AccountId,
+        token_b: AccountId,
+        rate_numerator: U128,
+        rate_denominator: U128,
+    ) -> u64 {
+        require!(
+            rate_numerator.0 > 0 && rate_denominator.0 > 0,
+            "Rate must be positive"
+        );
+        self.pools.push(&MockPool {
+            token_a,
+            token_b,
+            rate_numerator,
+            rate_denominator,
+        });
+        self.pools.len() - 1
+    }
+
+    pub fn set_slippage_bps(&mut self, slippage_bps: u32) {
+        require!(
+            slippage_bps as u128 <= BPS_DENOMINATOR,
+            "Slippage above 100%"
+        );
+        self.slippage_bps = slippage_bps;
+    }
+
+    /// Makes any swap routed through `pool_id` fail with `mode`.
+    pub fn set_swap_failure(&mut self, pool_id: u64, mode: FailureMode) {
+        if mode == FailureMode::None {
+            self.swap_failures.remove(&pool_id);
+        } else {
+            self.swap_failures.insert(&pool_id, &mode);
+        }
+    }
+
+    /// Makes any withdraw of `token_id` fail with `mode`.
+    pub fn set_withdraw_failure(&mut self, token_id: AccountId, mode: FailureMode) {
+        if mode == FailureMode::None {
+            self.withdraw_failures.remove(&token_id);
+        } else {
+            self.withdraw_failures.insert(&token_id, &mode);
+        }
+    }
+
+    /// Registers `account_id` (or the caller). The whole deposit stays available.
+    #[payable]
+    #[allow(unused_variables)]
+    pub fn storage_deposit(
+        &mut self,
+        account_id: Option<AccountId>,
+        registration_only: Option<bool>,
+    ) -> StorageBalance {
+        let account_id = account_id.unwrap_or_else(env::predecessor_account_id);
+        let total = self
+            .storage_balances
+            .get(&account_id)
+            .unwrap_or(0)
+            .checked_add(env::attached_deposit())
+            .expect("Storage balance overflows u128");
+        self.storage_balances.insert(&account_id, &total);
+        StorageBalance {
+            total: U128(total),
+            available: U128(total),
+        }
+    }
+
+    pub fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
+        self.storage_balances
+            .get(&account_id)
+            .map(|total| StorageBalance {
+                total: U128(total),
+                available: U128(total),
+            })
+    }
+
+    /// Credits the transferred tokens to `sender_id`'s deposits.
+    #[allow(unused_variables)]
+    pub fn ft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        self.assert_registered(&sender_id);
+        self.internal_deposit(&sender_id, &env::predecessor_account_id(), amount.0);
+        PromiseOrValue::Value(U128(0))
+    }
+
+    /// Executes the actions against the caller's deposits. An action without `amount_in`
+    /// spends the output of the previous one. Returns the output of the last action.
+    #[payable]
+    #[allow(unused_variables)]
+    pub fn swap(&mut self, actions: Vec<SwapAction>, referral_id: Option<AccountId>) -> U128 {
+        require!(!actions.is_empty(), "E72: at least one swap");
+        let sender_id = env::predecessor_account_id();
+        self.assert_registered(&sender_id);
+        let mut prev_amount_out: Option<Balance> = None;
+        for action in actions {
+            if let Some(mode) = self.swap_failures.get(&action.pool_id) {
+                mode.apply("swap");
+            }
+            let amount_in = action
+                .amount_in
+                .map(|amount| amount.0)
+                .or(prev_amount_out)
+                .expect("Missing amount_in");
+            self.internal_withdraw(&sender_id, &action.token_in, amount_in);
+            let quote = self.internal_get_return(
+                action.pool_id,
+                &action.token_in,
+                amount_in,
+                &action.token_out,
+            );
+            let amount_out = quote
+                .checked_mul(BPS_DENOMINATOR - self.slippage_bps as u128)
+                .expect("Swap amount overflows u128")
+                / BPS_DENOMINATOR;
+            require!(amount_out >= action.min_amount_out.0, "E68: slippage error");
+            self.internal_deposit(&sender_id, &action.token_out, amount_out);
+            prev_amount_out = Some(amount_out);
+        }
+        U128(prev_amount_out.unwrap())
+    }
+
+    /// Sends `amount` of `token_id` from the caller's deposits back to the caller.
+    /// Requires exactly 1 yocto attached, like Ref.
+    #[payable]
+    #[allow(unused_variables)]
+    pub fn withdraw(
+        &mut self,
+        token_id: AccountId,
+        amount: U128,
+        unregister: Option<bool>,
+    ) -> Promise {
+        assert_one_yocto();
+        if let Some(mode) = self.withdraw_failures.get(&token_id) {
+            mode.apply("withdraw");
+        }
+        let sender_id = env::predecessor_account_id();
+        self.assert_registered(&sender_id);
+        self.internal_withdraw(&sender_id, &token_id, amount.0);
+        ext_ft::ext(token_id.clone())
+            .with_attached_deposit(1)
+            .with_static_gas(GAS_FOR_FT_TRANSFER)
+            .ft_transfer(sender_id.clone(), amount, None)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RESOLVE_WITHDRAW)
+                    .exchange_callback_post_withdraw(token_id, sender_id, amount),
+            )
+    }
+
+    /// Re-credits the deposit if the outgoing transfer failed.
+    #[private]
+    pub fn exchange_callback_post_withdraw(
+        &mut self,
+        token_id: AccountId,
+        sender_id: AccountId,
+        amount: U128,
+    ) {
+        if !is_promise_success() {
+            env::log_str(&format!(
+                "Withdraw of {} {} failed, re-depositing",
+                amount.0, token_id
+            ));
+            self.internal_deposit(&sender_id, &token_id, amount.0);
+        }
+    }
+
+    /// Quote without slippage.
+    pub fn get_return(
+        &self,
+        pool_id: u64,
+        token_in: AccountId,
+        amount_in: U128,
+        token_out: AccountId,
+    ) -> U128 {
+        U128(self.internal_get_return(pool_id, &token_in, amount_in.0, &token_out))
+    }
+
+    pub fn get_deposits(&self, account_id: AccountId) -> HashMap<AccountId, U128> {
+        self.deposits
+            .get(&account_id)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(token_id, amount)| (token_id, U128(amount)))
+            .collect()
+    }
+
+    pub fn get_deposit(&self, account_id: AccountId, token_id: AccountId) -> U128 {
+        U128(
+            self.deposits
+                .get(&account_id)
+                .and_then(|d| d.get(&token_id).copied())
+                .unwrap_or(0),
+        )
+    }
+
+    pub fn get_pool(&self, pool_id: u64) -> MockPool {
+        self.pools.get(pool_id).expect("Pool not found")
+    }
+}
+
+impl MockRefExchange {
+    fn internal_get_return(
+        &self,
+        pool_id: u64,
+        token_in: &AccountId,
+        amount_in: Balance,
+        token_out: &AccountId,
+    ) -> Balance {
+        let pool = self.pools.get(pool_id).expect("Pool not found");
+        if *token_in == pool.token_a && *token_out == pool.token_b {
+            amount_in
+                .checked_mul(pool.rate_numerator.0)
+                .expect("Swap amount overflows u128")
+                / pool.rate_denominator.0
+        } else {
+            require!(
+                *token_in == pool.token_b && *token_out == pool.token_a,
+                "Tokens do not match the pool"
+            );
+            amount_in
+                .checked_mul(pool.rate_denominator.0)
+                .expect("Swap amount overflows u128")
+                / pool.rate_numerator.0
+        }
+    }
+
+    fn internal_deposit(&mut self, account_id: &AccountId, token_id: &AccountId, amount: Balance) {
+        let mut deposits = self.deposits.get(account_id).unwrap_or_default();
+        let balance = deposits.entry(token_id.clone()).or_insert(0);
+        *balance = balance.checked_add(amount).expect("Deposit overflows u128");
+        self.deposits.insert(account_id, &deposits);
+    }
+
+    fn assert_registered(&self, account_id: &AccountId) {
+        require!(
+            self.storage_balances.contains_key(account_id),
+            "E10: account not registered"
+        );
+    }
+
+    fn internal_withdraw(&mut self, account_id: &AccountId, token_id: &AccountId, amount: Balance) {
+        let mut deposits = self.deposits.get(account_id).unwrap_or_default();
+        let balance = deposits.get(token_id).copied().unwrap_or(0);
+        require!(balance >= amount, "E22: not enough tokens in deposit");
+        deposits.insert(token_id.clone(), balance - amount);
+        self.deposits.insert(account_id, &deposits);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::mock::VmAction;
+    use near_sdk::test_utils::{accounts, get_created_receipts, VMContextBuilder};
+    use near_sdk::{serde_json, testing_env, PromiseResult, RuntimeFeesConfig, VMConfig};
+
+    fn token(name: &str) -> AccountId {
+        format!("{}.near", name).parse().unwrap()
+    }
+
+    fn context(predecessor: AccountId, deposit: Balance) {
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(predecessor)
+            .attached_deposit(deposit)
+            .build());
+    }
+
+    /// Pool 0 trades 2 `a` for 3 `b`, pool 1 trades `b` for `c` at par.
+    /// `accounts(1)` is registered, `accounts(2)` is not.
+    fn setup() -> MockRefExchange {
+        context(accounts(0), 0);
+        let mut contract = MockRefExchange::new();
+        contract.add_pool(token("a"), token("b"), U128(3), U128(2));
+        contract.add_pool(token("b"), token("c"), U128(1), U128(1));
+        context(accounts(1), 0);
+        contract.storage_deposit(None, None);
+        contract
+    }
+
+    fn resolve_withdraw(contract: &mut MockRefExchange, result: PromiseResult) {
+        let exchange_id = VMContextBuilder::new().build().current_account_id;
+        testing_env!(
+            VMContextBuilder::new()
+                .predecessor_account_id(exchange_id)
+                .build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![result],
+        );
+        contract.exchange_callback_post_withdraw(token("a"), accounts(1), U128(40));
+    }
+
+    fn deposit(contract: &mut MockRefExchange, token_id: AccountId, amount: Balance) {
+        context(token_id, 0);
+        contract.ft_on_transfer(accounts(1), U128(amount), String::new());
+        context(accounts(1), 1);
+    }
+
+    fn action(
+        pool_id: u64,
+        token_in: &str,
+        amount_in: Option<Balance>,
+        token_out: &str,
+    ) -> SwapAction {
+        SwapAction {
+            pool_id,
+            token_in: token(token_in),
+            amount_in: amount_in.map(U128),
+            token_out: token(token_out),
+            min_amount_out: U128(0),
+        }
+    }
+
+    #[test]
+    fn test_get_return_both_directions() {
+        let contract = setup();
+        assert_eq!(
+            contract.internal_get_return(0, &token("a"), 100, &token("b")),
+            150
+        );
+        assert_eq!(
+            contract.internal_get_return(0, &token("b"), 150, &token("a")),
+            100
+        );
+        // 100 * 2 / 3 rounds down.
+        assert_eq!(
+            contract.internal_get_return(0, &token("b"), 100, &token("a")),
+            66
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Tokens do not match the pool")]
+    fn test_get_return_wrong_tokens() {
+        let contract = setup();
+        contract.internal_get_return(0, &token("a"), 100, &token("c"));
+    }
+
+    #[test]
+    fn test_swap_chained_actions() {
+        let mut contract = setup();
+        deposit(&mut contract, token("a"), 1000);
+        let amount_out = contract.swap(
+            vec![action(0, "a", Some(1000), "b"), action(1, "b", None, "c")],
+            None,
+        );
+        assert_eq!(amount_out.0, 1500);
+        assert_eq!(contract.get_deposit(accounts(1), token("a")).0, 0);
+        assert_eq!(contract.get_deposit(accounts(1), token("b")).0, 0);
+        assert_eq!(contract.get_deposit(accounts(1), token("c")).0, 1500);
+    }
+
+    #[test]
+    fn test_swap_with_slippage() {
+        let mut contract = setup();
+        contract.set_slippage_bps(100);
+        deposit(&mut contract, token("b"), 1999);
+        // 1999 * 9900 / 10000 = 1979.01, rounded down.
+        let amount_out = contract.swap(vec![action(1, "b", Some(1999), "c")], None);
+        assert_eq!(amount_out.0, 1979);
+    }
+
+    #[test]
+    #[should_panic(expected = "Slippage above 100%")]
+    fn test_set_slippage_bps_too_high() {
+        let mut contract = setup();
+        contract.set_slippage_bps(10_001);
+    }
+
+    #[test]
+    #[should_panic(expected = "E68: slippage error")]
+    fn test_swap_min_amount_out() {
+        let mut contract = setup();
+        contract.set_slippage_bps(1);
+        deposit(&mut contract, token("b"), 1000);
+        let mut swap_action = action(1, "b", Some(1000), "c");
+        swap_action.min_amount_out = U128(1000);
+        contract.swap(vec![swap_action], None);
+    }
+
+    #[test]
+    #[should_panic(expected = "E22: not enough tokens in deposit")]
+    fn test_swap_insufficient_deposit() {
+        let mut contract = setup();
+        deposit(&mut contract, token("a"), 10);
+        contract.swap(vec![action(0, "a", Some(11), "b")], None);
+    }
+
+    #[test]
+    #[should_panic(expected = "E72: at least one swap")]
+    fn test_swap_no_actions() {
+        let mut contract = setup();
+        contract.swap(vec![], None);
+    }
+
+    #[test]
+    fn test_swap_failure_only_affects_its_pool() {
+        let mut contract = setup();
+        contract.set_swap_failure(0, FailureMode::Panic);
+        deposit(&mut contract, token("b"), 100);
+        assert_eq!(
+            contract.swap(vec![action(1, "b", Some(100), "c")], None).0,
+            100
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Injected failure in swap")]
+    fn test_swap_failure_panic() {
+        let mut contract = setup();
+        contract.set_swap_failure(0, FailureMode::Panic);
+        deposit(&mut contract, token("a"), 100);
+        contract.swap(vec![action(0, "a", Some(100), "b")], None);
+    }
+
+    #[test]
+    fn test_swap_failure_reset() {
+        let mut contract = setup();
+        contract.set_swap_failure(0, FailureMode::Panic);
+        contract.set_swap_failure(0, FailureMode::None);
+        deposit(&mut contract, token("a"), 100);
+        assert_eq!(
+            contract.swap(vec![action(0, "a", Some(100), "b")], None).0,
+            150
+        );
+    }
+
+    #[test]
+    fn test_withdraw() {
+        let mut contract = setup();
+        deposit(&mut contract, token("a"), 100);
+        contract.withdraw(token("a"), U128(40), None);
+        assert_eq!(contract.get_deposit(accounts(1), token("a")).0, 60);
+
+        let receipts = get_created_receipts();
+        assert_eq!(receipts.len(), 2);
+        assert_eq!(receipts[0].receiver_id, token("a"));
+        match &receipts[0].actions[..] {
+            [VmAction::FunctionCall {
+                function_name,
+                args,
+                gas,
+                deposit,
+            }] => {
+                assert_eq!(function_name, "ft_transfer");
+                assert_eq!(*gas, GAS_FOR_FT_TRANSFER);
+                assert_eq!(*deposit, 1);
+                let args: serde_json::Value = serde_json::from_slice(args).unwrap();
+                assert_eq!(
+                    args,
+                    serde_json::json!({
+                        "receiver_id": accounts(1),
+                        "amount": "40",
+                        "memo": null,
+                    })
+                );
+            }
+            actions => panic!("Unexpected transfer actions {:?}", actions),
+        }
+    }
+
+    #[test]
+    fn test_withdraw_callback_failed_transfer_redeposits() {
+        let mut contract = setup();
+        deposit(&mut contract, token("a"), 100);
+        contract.withdraw(token("a"), U128(40), None);
+        resolve_withdraw(&mut contract, PromiseResult::Failed);
+        assert_eq!(contract.get_deposit(accounts(1), token("a")).0, 100);
+    }
+
+    #[test]
+    fn test_withdraw_callback_successful_transfer() {
+        let mut contract = setup();
+        deposit(&mut contract, token("a"), 100);
+        contract.withdraw(token("a"), U128(40), None);
+        resolve_withdraw(&mut contract, PromiseResult::Successful(vec![]));
+        assert_eq!(contract.get_deposit(accounts(1), token("a")).0, 60);
+    }
+
+    #[test]
+    #[should_panic(expected = "E10: account not registered")]
+    fn test_deposit_not_registered() {
+        let mut contract = setup();
+        context(token("a"), 0);
+        contract.ft_on_transfer(accounts(2), U128(100), String::new());
+    }
+
+    #[test]
+    #[should_panic(expected = "E10: account not registered")]
+    fn test_swap_not_registered() {
+        let mut contract = setup();
+        context(accounts(2), 1);
+        contract.swap(vec![action(0, "a", Some(100), "b")], None);
+    }
+
+    #[test]
+    #[should_panic(expected = "E10: account not registered")]
+    fn test_withdraw_not_registered() {
+        let mut contract = setup();
+        context(accounts(2), 1);
+        contract.withdraw(token("a"), U128(40), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Swap amount overflows u128")]
+    fn test_get_return_overflow() {
+        let contract = setup();
+        contract.internal_get_return(0, &token("a"), u128::MAX, &token("b"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires attached deposit of exactly 1 yoctoNEAR")]
+    fn test_withdraw_requires_one_yocto() {
+        let mut contract = setup();
+        deposit(&mut contract, token("a"), 100);
+        context(accounts(1), 0);
+        contract.withdraw(token("a"), U128(40), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Injected failure in withdraw")]
+    fn test_withdraw_failure_panic() {
+        let mut contract = setup();
+        contract.set_withdraw_failure(token("a"), FailureMode::Panic);
+        deposit(&mut contract, token("a"), 100);
+        contract.withdraw(token("a"), U128(40), None);
+    }
+
+    #[test]
+    fn test_storage_deposit() {
+        let mut contract = setup();
+        context(accounts(1), 100);
+        let balance = contract.storage_deposit(None, None);
+        assert_eq!(balance.total.0, 100);
+        assert_eq!(balance.available.0, 100);
+        assert_eq!(
+            contract.storage_balance_of(accounts(1)).unwrap().total.0,
+            100
+        );
+        assert!(contract.storage_balance_of(accounts(2)).is_none());
+    }
+}